@@ -33,6 +33,57 @@ generate_storage_ty! {
         mode : Mode,
         my_str: &'static str,
         my_bytes: &'static [u8],
+        #[encrypted]
+        password: &'static [u8],
+        #[encrypted]
+        pin: u32,
+    }
+}
+
+/// Toy keystream, use AES-CTR or ChaCha20 with device secret in firmware
+struct XorCipher(u8);
+
+impl StorageCipher for XorCipher {
+    fn apply_keystream(&mut self, nonce: u64, pos: usize, data: &mut [u8]) {
+        for (i, b) in data.iter_mut().enumerate() {
+            *b ^= self.0 ^ (nonce as u8).wrapping_add((pos + i) as u8);
+        }
+    }
+}
+
+/// Test memory split into 4 sectors, so log can rotate through them
+struct SectorMem(nor_storage::TestMem<0x100>);
+
+impl StorageMemRead for SectorMem {
+    type Error = ();
+
+    fn read(&self, offset_words: usize) -> u32 {
+        self.0.read(offset_words)
+    }
+
+    fn read_slice(&self, offset_start: usize, offset_end: usize) -> &'static [u32] {
+        self.0.read_slice(offset_start, offset_end)
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn sector_words(&self) -> usize {
+        0x40
+    }
+}
+
+impl StorageMem for SectorMem {
+    fn write(&mut self, offset_words: usize, word: u32) -> Result<(), Self::Error> {
+        self.0.write(offset_words, word)
+    }
+
+    fn erase(&mut self, sector: usize) -> Result<(), Self::Error> {
+        let sector_words = self.sector_words();
+        let start = sector * sector_words;
+        self.0.0[start .. start + sector_words].fill(!0);
+        Ok(())
     }
 }
 
@@ -42,35 +93,48 @@ fn crc32_ethernet() -> Digest {
 
 fn main() {
     
-    let mem = nor_storage::TestMem([!0;0x100]);
+    let mem = SectorMem(nor_storage::TestMem([!0;0x100]));
 
-    let mut storage = PerMap::<_, Digest>::new(mem);
-    let mut crc = crc32_ethernet();
-    let _ = storage.init(&mut crc);
+    // Encrypted fields need wear leveling
+    let storage = Storage::new(mem, crc32_ethernet())
+        .max_record_size(0x40)
+        .verify_writes(true)
+        .wear_leveling(true);
+    let mut storage = PerMap::mount_with(storage).unwrap();
     
-    storage.set_name(7u32, &mut crc).unwrap();
-    storage.set_name(6u32, &mut crc).unwrap();
-    storage.set_name(3u32, &mut crc).unwrap();
-    storage.set_name(1u32, &mut crc).unwrap();
-    storage.set_my_str("Hello", &mut crc).unwrap();
-    storage.set_calib(777u32, &mut crc).unwrap();
-    storage.set_cara(42u8, &mut crc).unwrap();
-    storage.set_cara(42u8, &mut crc).unwrap();
-    storage.set_cara(42u8, &mut crc).unwrap();
-    storage.set_flag(true, &mut crc).unwrap();
-    storage.set_flag(false, &mut crc).unwrap();
-    storage.set_my_str("Crabby crab", &mut crc).unwrap();
-    //storage.set_barray([false, true, false, true, true], &mut crc).unwrap();
-    //storage.set_barray([false; 5], &mut crc).unwrap();
-    storage.set_mode(Mode::Lifting, &mut crc).unwrap();
-    storage.set_mode(Mode::InAir, &mut crc).unwrap();
+    storage.set_name(7u32).unwrap();
+    storage.set_name(6u32).unwrap();
+    storage.set_name(3u32).unwrap();
+    storage.set_name(1u32).unwrap();
+    storage.set_my_str("Hello").unwrap();
+    storage.set_calib(777u32).unwrap();
+    storage.set_cara(42u8).unwrap();
+    storage.set_cara(42u8).unwrap();
+    storage.set_cara(42u8).unwrap();
+    storage.set_flag(true).unwrap();
+    storage.set_flag(false).unwrap();
+    storage.set_my_str("Crabby crab").unwrap();
+    //storage.set_barray([false, true, false, true, true]).unwrap();
+    //storage.set_barray([false; 5]).unwrap();
+    storage.set_mode(Mode::Lifting).unwrap();
+    storage.set_mode(Mode::InAir).unwrap();
     
-    storage.set_my_bytes(&[0u8,1,2], &mut crc).unwrap();
-    storage.set_my_bytes(&[2u8,1,0], &mut crc).unwrap();
+    storage.set_my_bytes(&[0u8,1,2]).unwrap();
+    storage.set_my_bytes(&[2u8,1,0]).unwrap();
 
-    let stats = storage.init(&mut crc);
+    let mut cipher = XorCipher(0x5A);
+    storage.set_password(b"hunter2", &mut cipher).unwrap();
+    storage.set_pin(1234, &mut cipher).unwrap();
+
+    let stats = storage.remount().unwrap();
     println!("Stats: {:#?}", stats);
     println!("{:?}", &storage);
+    println!("name history: {:?}", storage.history_name().collect::<Vec<_>>());
+
+    let mut buf = [0u8; 0x20];
+    let password = storage.get_password(&mut buf, &mut cipher).unwrap();
+    println!("password: {:?}", password.map(String::from_utf8_lossy));
+    println!("pin: {:?}", storage.get_pin(&mut cipher).unwrap());
 }
 
 
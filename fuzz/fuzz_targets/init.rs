@@ -0,0 +1,67 @@
+//! Scan arbitrary memory image, read back everything found and append record.
+//! First byte selects region size, including ones too small for a header.
+//!
+//!     cargo +nightly fuzz run init
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use crc::crc32::{Digest, IEEE};
+use crc::CalcType;
+
+use nor_storage::prelude::*;
+use nor_storage::TestMem;
+
+const TAGS: usize = 8;
+
+fn run<const N: usize>(data: &[u8]) {
+    let mut mem = TestMem::<N>::new();
+    for (word, bytes) in mem.0.iter_mut().zip(data.chunks(WORD_SZ)) {
+        let mut le = [0xFF; WORD_SZ];
+        le[.. bytes.len()].copy_from_slice(bytes);
+        *word = Word::from_le_bytes(le);
+    }
+
+    let mut table = [RecordDesc { tag: 0, ptr: None }; TAGS];
+    for (tag, desc) in table.iter_mut().enumerate() {
+        desc.tag = tag as Word;
+    }
+
+    let mut storage = Storage::new(mem, Digest::new_custom(IEEE, !0u32, 0u32, CalcType::Normal)).mount(table).unwrap();
+    let stats = *storage.stats();
+    assert!(storage.len() <= storage.capacity());
+    assert_eq!(stats.free_bytes, storage.capacity() - storage.len());
+
+    for desc in storage.table() {
+        if desc.ptr.is_some() {
+            assert!(storage.get(desc.tag).unwrap().is_some());
+        }
+        assert_eq!(storage.history(desc.tag).last(), storage.get(desc.tag).unwrap());
+    }
+    assert_eq!(storage.iter().count(), stats.records);
+
+    // Append must either fail gracefully or be found by the next scan
+    let payload = data.get(.. 7).unwrap_or(data);
+    if storage.update(1, payload).is_ok() {
+        storage.remount().unwrap();
+        assert_eq!(storage.get(1).unwrap(), Some(payload));
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let (size, data) = match data.split_first() {
+        Some((size, data)) => (*size, data),
+        None => return,
+    };
+    match size % 8 {
+        0 => run::<0>(data),
+        1 => run::<1>(data),
+        2 => run::<2>(data),
+        3 => run::<3>(data),
+        4 => run::<5>(data),
+        5 => run::<0x11>(data),
+        6 => run::<0x40>(data),
+        _ => run::<0x100>(data),
+    }
+});
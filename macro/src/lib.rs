@@ -38,7 +38,7 @@ pub fn generate_storage_ty(input: proc_macro::TokenStream) -> proc_macro::TokenS
         unimplemented!("Only structs supported")
     };
     //eprint!("fields : {:#?}", &fields);
-    let setters_getters = setters_getters(
+    let (setters, getters) = setters_getters(
         &fields.iter().enumerate().map(|(uid, f)|{
             (
                 f.clone(),
@@ -64,6 +64,21 @@ pub fn generate_storage_ty(input: proc_macro::TokenStream) -> proc_macro::TokenS
         Ident::new(&format!("get_{}", name.to_string()), name.span())
     }).collect();
 
+    // Encrypted fields need cipher to be read
+    let debug_fields : Vec<_> = fields.into_iter().zip(&getter_names).map(|(field, getter_name)| {
+        let name = &field.ident;
+        if is_encrypted(field) {
+            quote!(write!(f, "    {} : <encrypted>\n", stringify!(#name))?;)
+        } else {
+            quote!(
+                match self.#getter_name() {
+                    Ok(value) => write!(f, "    {} : {:?}\n", stringify!(#name), value)?,
+                    Err(e) => write!(f, "    {} : <{}>\n", stringify!(#name), e)?,
+                }
+            )
+        }
+    }).collect();
+
     let _tail_names : Vec<_> = (&field_name).into_iter().map(|name| {
         Ident::new(&format!("pos_{}", name.to_string()), name.span())
     }).collect();
@@ -88,52 +103,84 @@ pub fn generate_storage_ty(input: proc_macro::TokenStream) -> proc_macro::TokenS
         //const MAX_RECORDS_NUMBER : usize = #max_recods_num + 1;
 
         pub struct #ty_name<M, H> {
-            storage: Storage<M, H>,
-            record_table: [RecordDesc; #max_recods_num + 1],
+            storage: MountedStorage<M, H, [RecordDesc; #max_recods_num + 1]>,
         }
 
         impl<M, H> #ty_name<M, H> 
         where 
-            M: StorageMem,
+            M: StorageMemRead,
             M::Error: ::core::fmt::Debug,
-            H: StorageHasher32,
+            H: StorageHasher,
         {
-            pub fn new(mem: M) -> Self {
-                Self {
-                    storage: Storage::<M, H>::new(mem),
-                    record_table: [
-                        RecordDesc {
-                            tag: 0,
-                            ptr: None,
-                        },
-                        #(RecordDesc {
-                            tag: #uids,
-                            ptr: None,
-                        }),*
-                    ],
-                }
+            /// Scan storage memory and give access to fields
+            pub fn mount(mem: M, hasher: H) -> Result<Self, Error<M::Error>> {
+                Self::mount_with(Storage::new(mem, hasher))
+            }
+
+            /// Same as `mount` for configured storage, e.g. with maximum record size
+            pub fn mount_with(storage: Storage<M, H>) -> Result<Self, Error<M::Error>> {
+                let record_table = [
+                    RecordDesc {
+                        tag: 0,
+                        ptr: None,
+                    },
+                    #(RecordDesc {
+                        tag: #uids,
+                        ptr: None,
+                    }),*
+                ];
+                Ok(Self {
+                    storage: storage.mount(record_table)?,
+                })
+            }
+
+            pub fn remount(&mut self) -> Result<InitStats, Error<M::Error>> {
+                self.storage.remount()
+            }
+
+            pub fn stats(&self) -> &InitStats {
+                self.storage.stats()
             }
 
-            pub fn init(&mut self, hasher: &mut H) -> InitStats {
-                self.storage.init(&mut self.record_table, hasher)
+            pub fn iter(&self) -> Iter<'_, M, H> {
+                self.storage.iter()
             }
 
-            #setters_getters
+            pub fn iter_live(&self) -> IterLive<'_, M, H> {
+                self.storage.iter_live()
+            }
+
+            pub fn storage(&self) -> &MountedStorage<M, H, [RecordDesc; #max_recods_num + 1]> {
+                &self.storage
+            }
+
+            /// Give back mounted storage, e.g. to build factory image of field
+            /// values with `ImageBuilder::from_storage`
+            pub fn into_storage(self) -> MountedStorage<M, H, [RecordDesc; #max_recods_num + 1]> {
+                self.storage
+            }
+
+            #getters
         }
 
-        impl<M, H> ::core::fmt::Debug for #ty_name<M, H>
+        impl<M, H> #ty_name<M, H> 
         where 
             M: StorageMem,
             M::Error: ::core::fmt::Debug,
-            H: StorageHasher32,
+            H: StorageHasher,
+        {
+            #setters
+        }
+
+        impl<M, H> ::core::fmt::Debug for #ty_name<M, H>
+        where 
+            M: StorageMemRead,
+            M::Error: ::core::fmt::Debug,
+            H: StorageHasher,
         {
             fn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {
                     write!(f, "{} {{\n", stringify!(#ty_name))?;
-                    #( 
-                        let name_str = stringify!( #field_name );
-                        let value = self.#getter_names(None).unwrap();
-                        write!(f, "    {} : {:?}\n", name_str, value)?;
-                    )*
+                    #( #debug_fields )*
                     write!(f, "}}\n")
             }
         }
@@ -142,9 +189,11 @@ pub fn generate_storage_ty(input: proc_macro::TokenStream) -> proc_macro::TokenS
     proc_macro::TokenStream::from(out)
 }
 
-fn setters_getters(fields: &Vec<(Field, LitInt)>) -> TokenStream {
-    let mut tt = TokenStream::new();
+fn setters_getters(fields: &Vec<(Field, LitInt)>) -> (TokenStream, TokenStream) {
+    let mut setters = TokenStream::new();
+    let mut getters = TokenStream::new();
     for (f, uid) in fields {
+        let encrypted = is_encrypted(f);
         match f {
             // Matching &'static types
             Field{
@@ -166,8 +215,13 @@ fn setters_getters(fields: &Vec<(Field, LitInt)>) -> TokenStream {
                                 .expect("Unsupported strange type behind ref");
 
                             if *ident == "str" {
-                                let sg = setter_getter_static_str(ident_name, uid);
-                                tt.extend(sg);
+                                let (s, g) = if encrypted {
+                                    setter_getter_encrypted_str(ident_name, uid)
+                                } else {
+                                    setter_getter_static_str(ident_name, uid)
+                                };
+                                setters.extend(s);
+                                getters.extend(g);
                             } else {
                                unimplemented!("Unsupported field type behind reference")
                             }
@@ -180,8 +234,13 @@ fn setters_getters(fields: &Vec<(Field, LitInt)>) -> TokenStream {
                                     .expect("Unsupported strange type behind ref");
 
                                 if *ident == "u8" {
-                                    let sg = setter_getter_static_byte_slice(ident_name, uid);
-                                    tt.extend(sg);
+                                    let (s, g) = if encrypted {
+                                        setter_getter_encrypted_byte_slice(ident_name, uid)
+                                    } else {
+                                        setter_getter_static_byte_slice(ident_name, uid)
+                                    };
+                                    setters.extend(s);
+                                    getters.extend(g);
                                 } else {
                                    unimplemented!("Unsupported field type behind reference")
                                 }
@@ -201,28 +260,33 @@ fn setters_getters(fields: &Vec<(Field, LitInt)>) -> TokenStream {
                     .first()
                     .expect("Unsupported strange type behind ref");
                 
-                let sg = setter_getter_primitive_composite(ident_name, ty, uid);
-                tt.extend(sg);
+                let (s, g) = if encrypted {
+                    setter_getter_encrypted_primitive_composite(ident_name, ty, uid)
+                } else {
+                    setter_getter_primitive_composite(ident_name, ty, uid)
+                };
+                setters.extend(s);
+                getters.extend(g);
             }
 
             _ => unimplemented!("Unsupported field type"),
         }
     }
 
-    tt
+    (setters, getters)
 }
 
-fn setter_getter_primitive_composite(name: &Ident, ty: &Ident, uid: &LitInt) -> TokenStream {
+fn setter_getter_primitive_composite(name: &Ident, ty: &Ident, uid: &LitInt) -> (TokenStream, TokenStream) {
     let setter_name = Ident::new(&("set_".to_string() + &name.to_string()), Span::call_site());
     let getter_name = Ident::new(&("get_".to_string() + &name.to_string()), Span::call_site());
-    quote!(
-        pub fn #setter_name(&mut self, #name: #ty, hasher: &mut H)
+    let history_name = Ident::new(&("history_".to_string() + &name.to_string()), Span::call_site());
+    let setter = quote!(
+        pub fn #setter_name(&mut self, #name: #ty)
             -> Result<(),Error<M::Error>>
         {
-
-            assert!(::core::mem::align_of::<#ty>() <= ::core::mem::align_of::<Word>(), "Aligment of type to big");
-
-            let mut record_desc = &mut self.record_table[#uid];
+            if ::core::mem::align_of::<#ty>() > ::core::mem::align_of::<Word>() {
+                return Err(Error::Alignment);
+            }
 
             let src = unsafe { 
                 ::core::slice::from_raw_parts(
@@ -230,14 +294,21 @@ fn setter_getter_primitive_composite(name: &Ident, ty: &Ident, uid: &LitInt) ->
                      ::core::mem::size_of::<#ty>(),
                 ) 
             };
-            self.storage.update(record_desc, src, hasher)
+            self.storage.update(#uid, src)
         }
+    );
+    let getter = quote!(
+        pub fn #getter_name(&self) ->  Result<Option<&'static #ty>, Error<M::Error>> {
+            if ::core::mem::align_of::<#ty>() > ::core::mem::align_of::<Word>() {
+                return Err(Error::Alignment);
+            }
 
-        pub fn #getter_name(&self, hasher: Option<&mut H>) ->  Result<Option<&'static #ty>, Error<M::Error>> {
-            let record_desc = &self.record_table[#uid];
-            let some = self.storage.get(record_desc, hasher)?;
+            let some = self.storage.get(#uid)?;
             
             match some {
+                Some(payload) if payload.len() != ::core::mem::size_of::<#ty>() => {
+                    Err(Error::Decode { tag: #uid })
+                }
                 Some(payload) => {
                     unsafe {
                         let field_ptr = payload.as_ptr() as usize as *const #ty;
@@ -247,65 +318,169 @@ fn setter_getter_primitive_composite(name: &Ident, ty: &Ident, uid: &LitInt) ->
                 None => Ok(None),
             }
         }
-    )
+
+        pub fn #history_name<'a>(&'a self) -> impl Iterator<Item = &'static #ty> + 'a {
+            self.storage.history(#uid)
+                .filter(|payload| payload.len() == ::core::mem::size_of::<#ty>())
+                .map(|payload| {
+                    unsafe { &*(payload.as_ptr() as usize as *const #ty) }
+                })
+        }
+    );
+
+    (setter, getter)
 }
 
-fn setter_getter_static_byte_slice(name: &Ident, uid: &LitInt) -> TokenStream {
+fn setter_getter_static_byte_slice(name: &Ident, uid: &LitInt) -> (TokenStream, TokenStream) {
     let setter_name = Ident::new(&("set_".to_string() + &name.to_string()), Span::call_site());
     let getter_name = Ident::new(&("get_".to_string() + &name.to_string()), Span::call_site());
-    quote!(
-        pub fn #setter_name(&mut self, #name: &[u8], hasher: &mut H)
+    let history_name = Ident::new(&("history_".to_string() + &name.to_string()), Span::call_site());
+    let setter = quote!(
+        pub fn #setter_name(&mut self, #name: &[u8])
             -> Result<(),Error<M::Error>>
         {
-            let mut record_desc = &mut self.record_table[#uid];
-            self.storage.update(record_desc, #name, hasher)
+            self.storage.update(#uid, #name)
         }
-
-        pub fn #getter_name(&self, hasher: Option<&mut H>) ->  Result<Option<&'static [u8]>, Error<M::Error>> {
-            let record_desc = &self.record_table[#uid];
-            let some = self.storage.get(record_desc, hasher)?;
+    );
+    let getter = quote!(
+        pub fn #getter_name(&self) ->  Result<Option<&'static [u8]>, Error<M::Error>> {
+            let some = self.storage.get(#uid)?;
             
             match some {
                 Some(payload) => Ok(Some(payload)),
                 None => Ok(None),
             }
         }
-    )
+
+        pub fn #history_name<'a>(&'a self) -> impl Iterator<Item = &'static [u8]> + 'a {
+            self.storage.history(#uid)
+        }
+    );
+
+    (setter, getter)
 }
 
-fn setter_getter_static_str(name: &Ident, uid: &LitInt) -> TokenStream {
+fn setter_getter_static_str(name: &Ident, uid: &LitInt) -> (TokenStream, TokenStream) {
     let setter_name = Ident::new(&("set_".to_string() + &name.to_string()), Span::call_site());
     let getter_name = Ident::new(&("get_".to_string() + &name.to_string()), Span::call_site());
-    quote!(
-        pub fn #setter_name(&mut self, #name: &str, hasher: &mut H)
+    let history_name = Ident::new(&("history_".to_string() + &name.to_string()), Span::call_site());
+    let setter = quote!(
+        pub fn #setter_name(&mut self, #name: &str)
             -> Result<(),Error<M::Error>>
         {
-            let mut record_desc = &mut self.record_table[#uid];
-            self.storage.update(record_desc, #name.as_bytes(), hasher)
+            self.storage.update(#uid, #name.as_bytes())
         }
-
-        pub fn #getter_name(&self, hasher: Option<&mut H>) ->  Result<Option<&'static str>, Error<M::Error>> {
-            let record_desc = &self.record_table[#uid];
-            let some = self.storage.get(record_desc, hasher)?;
+    );
+    let getter = quote!(
+        pub fn #getter_name(&self) ->  Result<Option<&'static str>, Error<M::Error>> {
+            let some = self.storage.get(#uid)?;
             
             match some {
                 Some(payload) => {
-                    let str = unsafe { ::core::str::from_utf8_unchecked(payload) };
+                    let str = ::core::str::from_utf8(payload)
+                        .map_err(|_| Error::Decode { tag: #uid })?;
                     Ok(Some(str))
                 }
                 None => Ok(None),
             }
         }
-    )
-}
-
-
 
+        pub fn #history_name<'a>(&'a self) -> impl Iterator<Item = &'static str> + 'a {
+            self.storage.history(#uid)
+                .filter_map(|payload| ::core::str::from_utf8(payload).ok())
+        }
+    );
 
+    (setter, getter)
+}
 
+/// Field marked with `#[encrypted]` is stored by `update_encrypted`, so
+/// storage needs wear leveling
+fn is_encrypted(f: &Field) -> bool {
+    f.attrs.iter().any(|a| a.path.is_ident("encrypted"))
+}
 
+fn setter_getter_encrypted_primitive_composite(name: &Ident, ty: &Ident, uid: &LitInt) -> (TokenStream, TokenStream) {
+    let setter_name = Ident::new(&("set_".to_string() + &name.to_string()), Span::call_site());
+    let getter_name = Ident::new(&("get_".to_string() + &name.to_string()), Span::call_site());
+    let setter = quote!(
+        pub fn #setter_name<C: StorageCipher>(&mut self, #name: #ty, cipher: &mut C)
+            -> Result<(),Error<M::Error>>
+        {
+            let src = unsafe { 
+                ::core::slice::from_raw_parts(
+                     (&#name) as *const _ as usize as *const u8,
+                     ::core::mem::size_of::<#ty>(),
+                ) 
+            };
+            self.storage.update_encrypted(#uid, src, cipher)
+        }
+    );
+    let getter = quote!(
+        pub fn #getter_name<C: StorageCipher>(&self, cipher: &mut C) ->  Result<Option<#ty>, Error<M::Error>> {
+            let mut buf = [0u8; ::core::mem::size_of::<#ty>()];
+            match self.storage.get_decrypted(#uid, &mut buf, cipher) {
+                Ok(Some(payload)) if payload.len() != ::core::mem::size_of::<#ty>() => {
+                    Err(Error::Decode { tag: #uid })
+                }
+                Ok(Some(_)) => {
+                    Ok(Some(unsafe { ::core::ptr::read_unaligned(buf.as_ptr() as *const #ty) }))
+                }
+                Ok(None) => Ok(None),
+                Err(Error::BufferTooSmall { .. }) => Err(Error::Decode { tag: #uid }),
+                Err(e) => Err(e),
+            }
+        }
+    );
 
+    (setter, getter)
+}
 
+fn setter_getter_encrypted_byte_slice(name: &Ident, uid: &LitInt) -> (TokenStream, TokenStream) {
+    let setter_name = Ident::new(&("set_".to_string() + &name.to_string()), Span::call_site());
+    let getter_name = Ident::new(&("get_".to_string() + &name.to_string()), Span::call_site());
+    let setter = quote!(
+        pub fn #setter_name<C: StorageCipher>(&mut self, #name: &[u8], cipher: &mut C)
+            -> Result<(),Error<M::Error>>
+        {
+            self.storage.update_encrypted(#uid, #name, cipher)
+        }
+    );
+    let getter = quote!(
+        pub fn #getter_name<'b, C: StorageCipher>(&self, buf: &'b mut [u8], cipher: &mut C)
+            -> Result<Option<&'b [u8]>, Error<M::Error>>
+        {
+            self.storage.get_decrypted(#uid, buf, cipher)
+        }
+    );
 
+    (setter, getter)
+}
 
+fn setter_getter_encrypted_str(name: &Ident, uid: &LitInt) -> (TokenStream, TokenStream) {
+    let setter_name = Ident::new(&("set_".to_string() + &name.to_string()), Span::call_site());
+    let getter_name = Ident::new(&("get_".to_string() + &name.to_string()), Span::call_site());
+    let setter = quote!(
+        pub fn #setter_name<C: StorageCipher>(&mut self, #name: &str, cipher: &mut C)
+            -> Result<(),Error<M::Error>>
+        {
+            self.storage.update_encrypted(#uid, #name.as_bytes(), cipher)
+        }
+    );
+    let getter = quote!(
+        pub fn #getter_name<'b, C: StorageCipher>(&self, buf: &'b mut [u8], cipher: &mut C)
+            -> Result<Option<&'b str>, Error<M::Error>>
+        {
+            match self.storage.get_decrypted(#uid, buf, cipher)? {
+                Some(payload) => {
+                    let str = ::core::str::from_utf8(payload)
+                        .map_err(|_| Error::Decode { tag: #uid })?;
+                    Ok(Some(str))
+                }
+                None => Ok(None),
+            }
+        }
+    );
 
+    (setter, getter)
+}
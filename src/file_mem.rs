@@ -0,0 +1,170 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::vec::Vec;
+use std::boxed::Box;
+
+use crate::{StorageMem, StorageMemRead, Word, WORD_SZ};
+
+#[derive(Debug)]
+pub enum FileMemError {
+    /// Write tries to flip bits from 0 to 1, NOR flash can't do it without erase
+    NotErased(usize),
+    Io(io::Error),
+}
+
+/// Storage memory buffered from file
+///
+/// Behaves like NOR flash: write can only clear bits. Memory opened with
+/// `persistent` writes every programmed word through to the file, so it
+/// survives restarts, other constructors give detached image.
+pub struct FileMem {
+    words: Box<[Word]>,
+    file: Option<File>,
+}
+
+impl FileMem {
+    /// Load detached image from file, e.g. raw flash dump. Its length should
+    /// be multiple of `WORD_SZ`
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        Ok(Self { words: Self::words_from_bytes(&bytes)?, file: None })
+    }
+
+    /// Open file backed memory of `size` bytes, erased file is created if
+    /// missing
+    pub fn persistent<P: AsRef<Path>>(path: P, size: usize) -> io::Result<Self> {
+        if !size.is_multiple_of(WORD_SZ) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Size is not multiple of word size"));
+        }
+
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let bytes = std::vec![0xFF; size];
+                fs::write(&path, &bytes)?;
+                bytes
+            }
+            Err(e) => return Err(e),
+        };
+        if bytes.len() != size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "File size doesn't match memory size"));
+        }
+
+        let file = OpenOptions::new().write(true).open(path)?;
+        Ok(Self { words: Self::words_from_bytes(&bytes)?, file: Some(file) })
+    }
+
+    /// Erased detached image of `len_words` words
+    pub fn erased(len_words: usize) -> Self {
+        Self { words: std::vec![!0; len_words].into_boxed_slice(), file: None }
+    }
+
+    /// Write image to file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    /// Image content as bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.words.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    fn words_from_bytes(bytes: &[u8]) -> io::Result<Box<[Word]>> {
+        if !bytes.len().is_multiple_of(WORD_SZ) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Image size is not multiple of word size"));
+        }
+
+        Ok(bytes
+            .chunks(WORD_SZ)
+            .map(|w| Word::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect::<Vec<_>>()
+            .into_boxed_slice())
+    }
+}
+
+impl StorageMemRead for FileMem {
+    type Error = FileMemError;
+
+    fn read(&self, offset_words : usize) -> Word {
+        self.words[offset_words]
+    }
+
+    fn read_slice(&self, offset_start : usize, offset_end : usize) -> &'static [Word] {
+        // Boxed slice is never reallocated while `FileMem` is alive
+        unsafe { core::mem::transmute(&self.words[offset_start .. offset_end]) }
+    }
+
+    fn len(&self) -> usize {
+        self.words.len()
+    }
+}
+
+impl StorageMem for FileMem {
+    fn write(&mut self, offset_words : usize, word : Word) -> Result<(), Self::Error> {
+        if word & !self.words[offset_words] != 0 {
+            return Err(FileMemError::NotErased(offset_words));
+        }
+
+        if let Some(file) = &mut self.file {
+            file.seek(SeekFrom::Start((offset_words * WORD_SZ) as u64))
+                .and_then(|_| file.write_all(&word.to_le_bytes()))
+                .map_err(FileMemError::Io)?;
+        }
+        self.words[offset_words] = word;
+        Ok(())
+    }
+
+    fn erase(&mut self, sector: usize) -> Result<(), Self::Error> {
+        let sector_words = self.sector_words();
+        let start = sector * sector_words;
+        let end = (start + sector_words).min(self.words.len());
+        if let Some(file) = &mut self.file {
+            file.seek(SeekFrom::Start((start * WORD_SZ) as u64))
+                .and_then(|_| file.write_all(&std::vec![0xFF; (end - start) * WORD_SZ]))
+                .map_err(FileMemError::Io)?;
+        }
+        self.words[start .. end].fill(!0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RecordDesc, Storage};
+    use crc::crc32::{Digest, IEEE};
+    use crc::CalcType;
+
+    fn crc32_new() -> Digest {
+        Digest::new_custom(IEEE, !0u32, 0u32, CalcType::Normal)
+    }
+
+    #[test]
+    fn persistent_test() {
+        let path = std::env::temp_dir().join(std::format!("nor-storage-{}.bin", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut mem = FileMem::persistent(&path, 0x400).unwrap();
+        assert_eq!(mem.len(), 0x100);
+        assert!(mem.write(0, 0xFFFF_0000).is_ok());
+        assert!(mem.write(0, 0xFF00_0000).is_ok());
+        assert!(matches!(mem.write(0, 0x0000_00FF), Err(FileMemError::NotErased(0))));
+
+        let desc = [RecordDesc { tag: 0, ptr: None }, RecordDesc { tag: 1, ptr: None }];
+        let mut storage = Storage::new(FileMem::persistent(&path, 0x400).unwrap(), crc32_new())
+            .mount(desc)
+            .unwrap();
+        storage.update(1, b"persist").unwrap();
+        drop(storage);
+
+        let storage = Storage::new(FileMem::persistent(&path, 0x400).unwrap(), crc32_new())
+            .mount(desc)
+            .unwrap();
+        assert_eq!(storage.get(1).unwrap().unwrap(), b"persist");
+        assert_eq!(storage.stats().first_corruption, Some(0));
+        assert!(FileMem::persistent(&path, 0x800).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
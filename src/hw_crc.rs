@@ -0,0 +1,133 @@
+use crate::{StorageHasher32, Word};
+
+/// CRC calculation unit fed word by word, e.g. CRC peripheral of MCU
+pub trait CrcPeripheral {
+    fn reset(&mut self);
+    fn feed(&mut self, word: Word);
+    fn read(&self) -> Word;
+}
+
+/// Hasher over CRC peripheral
+pub struct HwCrc<P>(pub P);
+
+impl<P: CrcPeripheral> StorageHasher32 for HwCrc<P> {
+    fn reset(&mut self) {
+        self.0.reset();
+    }
+
+    fn write32(&mut self, words: &[u32]) {
+        for word in words {
+            self.0.feed(*word);
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        self.0.read()
+    }
+}
+
+// CRC-32 polynomial, STM32 default
+const POLY: Word = 0x04C1_1DB7;
+
+/// Software model of STM32 CRC unit in default configuration: polynomial
+/// 0x04C11DB7, initial value 0xFFFFFFFF, word is fed MSB first, no
+/// reflection and no final xor
+///
+/// Same as CRC-32/MPEG-2 over big endian bytes of words, so images built on
+/// host with it are identical to images written by device using `HwCrc`.
+pub struct SoftStm32Crc {
+    crc: Word,
+}
+
+impl SoftStm32Crc {
+    pub fn new() -> Self {
+        Self { crc: !0 }
+    }
+}
+
+impl Default for SoftStm32Crc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CrcPeripheral for SoftStm32Crc {
+    fn reset(&mut self) {
+        self.crc = !0;
+    }
+
+    fn feed(&mut self, word: Word) {
+        let mut crc = self.crc ^ word;
+        for _ in 0 .. Word::BITS {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ POLY } else { crc << 1 };
+        }
+        self.crc = crc;
+    }
+
+    fn read(&self) -> Word {
+        self.crc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RecordDesc, Storage, TestMem};
+    use crc::crc32::{Digest, Hasher32, IEEE};
+    use crc::CalcType;
+
+    #[test]
+    fn stm32_vector_test() {
+        let mut crc = SoftStm32Crc::new();
+        crc.feed(0x1234_5678);
+        assert_eq!(crc.read(), 0xDF8A_8A2B);
+
+        crc.reset();
+        assert_eq!(crc.read(), !0);
+    }
+
+    #[test]
+    fn mpeg2_equivalence_test() {
+        let mut words = [0 as Word; 0x40];
+        let mut seed = 0x2545_F491u32;
+        for w in words.iter_mut() {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            *w = seed;
+        }
+
+        let mut hw = HwCrc(SoftStm32Crc::new());
+        let mut mpeg2 = Digest::new_custom(IEEE, !0u32, 0u32, CalcType::Normal);
+        for len in 0 .. words.len() {
+            StorageHasher32::reset(&mut hw);
+            StorageHasher32::write32(&mut hw, &words[.. len]);
+            Hasher32::reset(&mut mpeg2);
+            for w in &words[.. len] {
+                Hasher32::write(&mut mpeg2, &w.to_be_bytes());
+            }
+            assert_eq!(StorageHasher32::finish(&hw), mpeg2.sum32());
+        }
+    }
+
+    #[test]
+    fn storage_test() {
+        let table = [RecordDesc { tag: 0, ptr: None }, RecordDesc { tag: 1, ptr: None }];
+        let mut storage = Storage::new(TestMem::<0x40>::new(), HwCrc(SoftStm32Crc::new()))
+            .mount(table)
+            .unwrap();
+        storage.update(1, b"stm32").unwrap();
+
+        let mut crc = SoftStm32Crc::new();
+        for w in &storage.mem().words()[.. 2] {
+            crc.feed(*w);
+        }
+        for w in &storage.mem().words()[3 .. 5] {
+            crc.feed(*w);
+        }
+        assert_eq!(storage.mem().words()[2], crc.read());
+
+        storage.remount().unwrap();
+        assert_eq!(storage.get(1).unwrap().unwrap(), b"stm32");
+    }
+}
@@ -0,0 +1,187 @@
+use std::fmt::Write;
+use std::io;
+use std::string::String;
+use std::vec::Vec;
+
+use crate::{Error, FileMem, FileMemError, MountedStorage, RecordDesc, Storage, StorageHasher, Word, WORD_SZ};
+
+/// Host side builder of factory default storage images
+///
+/// Records are laid out by `MountedStorage::update`, so image is exactly the
+/// same as if firmware wrote them on first boot.
+///
+/// Values of generated storage type are turned into image by mounting it on
+/// `storage` and passing it to `from_storage` once fields are set:
+///
+/// ```ignore
+/// let mut defaults = PerMap::mount_with(image::storage(0x400, hasher)?)?;
+/// defaults.set_serial(b"SN0001")?;
+/// let bin = ImageBuilder::from_storage(defaults.into_storage()).to_bin();
+/// ```
+pub struct ImageBuilder<H, T = Vec<RecordDesc>> {
+    storage: MountedStorage<FileMem, H, T>,
+    fill: u8,
+}
+
+/// Erased storage for region of `size` bytes, which should be multiple
+/// of `WORD_SZ`
+pub fn storage<H: StorageHasher>(size: usize, hasher: H) -> Result<Storage<FileMem, H>, Error<FileMemError>> {
+    if !size.is_multiple_of(WORD_SZ) {
+        let e = io::Error::new(io::ErrorKind::InvalidInput, "Size is not multiple of word size");
+        return Err(Error::Driver(FileMemError::Io(e)));
+    }
+    Ok(Storage::new(FileMem::erased(size / WORD_SZ), hasher))
+}
+
+impl<H> ImageBuilder<H>
+where
+    H: StorageHasher,
+{
+    /// Builder of image for region of `size` bytes holding records with
+    /// tags below `tags`
+    pub fn new(size: usize, tags: usize, hasher: H) -> Result<Self, Error<FileMemError>> {
+        let table = (0 .. tags as Word).map(|tag| RecordDesc { tag, ptr: None }).collect();
+        Ok(Self::from_storage(storage(size, hasher)?.mount(table)?))
+    }
+}
+
+impl<H, T> ImageBuilder<H, T>
+where
+    H: StorageHasher,
+    T: AsRef<[RecordDesc]> + AsMut<[RecordDesc]>,
+{
+    /// Builder of image holding records already written to `storage`
+    pub fn from_storage(storage: MountedStorage<FileMem, H, T>) -> Self {
+        Self { storage, fill: 0xFF }
+    }
+
+    /// Fill value of space left after records, should match erased state
+    /// of flash or device will consider it written
+    pub fn fill(mut self, fill: u8) -> Self {
+        self.fill = fill;
+        self
+    }
+
+    /// Append record
+    pub fn record(&mut self, tag: Word, payload: &[u8]) -> Result<&mut Self, Error<FileMemError>> {
+        self.storage.update(tag, payload)?;
+        Ok(self)
+    }
+
+    /// Raw binary image
+    pub fn to_bin(&self) -> Vec<u8> {
+        let mut image = self.storage.mem().to_bytes();
+        for b in &mut image[self.storage.len() ..] {
+            *b = self.fill;
+        }
+        image
+    }
+
+    /// Intel HEX image placed at `base_addr`
+    pub fn to_intel_hex(&self, base_addr: u32) -> String {
+        to_intel_hex(&self.to_bin(), base_addr)
+    }
+}
+
+/// Encode binary image placed at `base_addr` as Intel HEX
+pub fn to_intel_hex(image: &[u8], base_addr: u32) -> String {
+    fn push_record(out: &mut String, addr: u16, ty: u8, data: &[u8]) {
+        let mut sum = (data.len() as u8)
+            .wrapping_add((addr >> 8) as u8)
+            .wrapping_add(addr as u8)
+            .wrapping_add(ty);
+        let _ = write!(out, ":{:02X}{:04X}{:02X}", data.len(), addr, ty);
+        for b in data {
+            sum = sum.wrapping_add(*b);
+            let _ = write!(out, "{:02X}", b);
+        }
+        let _ = writeln!(out, "{:02X}", sum.wrapping_neg());
+    }
+
+    let mut out = String::new();
+    let mut upper = None;
+    let mut pos = 0;
+    while pos < image.len() {
+        let addr = base_addr.wrapping_add(pos as u32);
+        // Extended linear address record on every 64K boundary, data record
+        // can't cross it
+        if upper != Some(addr >> 16) {
+            upper = Some(addr >> 16);
+            push_record(&mut out, 0, 0x04, &((addr >> 16) as u16).to_be_bytes());
+        }
+        let len = (0x1_0000 - (addr & 0xFFFF) as usize).min(16).min(image.len() - pos);
+        push_record(&mut out, addr as u16, 0x00, &image[pos .. pos + len]);
+        pos += len;
+    }
+    push_record(&mut out, 0, 0x01, &[]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::{StorageMemRead, TestMem};
+    use crc::crc32::{Digest, IEEE};
+    use crc::CalcType;
+
+    fn crc32_new() -> Digest {
+        Digest::new_custom(IEEE, !0u32, 0u32, CalcType::Normal)
+    }
+
+    #[test]
+    fn same_as_update_test() {
+        let mut builder = ImageBuilder::new(0x100 * WORD_SZ, 3, crc32_new()).unwrap();
+        builder
+            .record(1, b"serial").unwrap()
+            .record(2, &[0x10, 0x20]).unwrap();
+        assert!(matches!(builder.record(3, &[]), Err(Error::UnknownTag { tag: 3 })));
+        let image = builder.to_bin();
+
+        let mut storage = Storage::new(TestMem([!0; 0x100]), crc32_new())
+            .mount(builder.storage.table().to_vec())
+            .unwrap();
+        storage.update(1, b"serial").unwrap();
+        storage.update(2, &[0x10, 0x20]).unwrap();
+        let expected: Vec<u8> = storage.mem().read_slice(0, 0x100)
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect();
+
+        assert_eq!(image, expected);
+    }
+
+    #[test]
+    fn intel_hex_test() {
+        let hex = to_intel_hex(&[0x01, 0x02], 0x0800_0000);
+        assert_eq!(hex, ":020000040800F2\n:020000000102FB\n:00000001FF\n");
+
+        // Record is split at 64K boundary
+        let hex = to_intel_hex(&[0x01, 0x02, 0x03, 0x04], 0xFFFE);
+        assert_eq!(hex, ":020000040000FA\n:02FFFE000102FE\n:020000040001F9\n:020000000304F7\n:00000001FF\n");
+    }
+
+    generate_storage_ty! {
+        struct Defaults {
+            serial: &'static str,
+            calib: u32,
+        }
+    }
+
+    #[test]
+    fn generated_type_test() {
+        assert!(matches!(storage(0x101, crc32_new()), Err(Error::Driver(FileMemError::Io(_)))));
+        assert!(ImageBuilder::new(0x102, 3, crc32_new()).is_err());
+
+        let mut defaults = Defaults::mount_with(storage(0x100, crc32_new()).unwrap()).unwrap();
+        defaults.set_serial("SN0001").unwrap();
+        defaults.set_calib(777).unwrap();
+        let image = ImageBuilder::from_storage(defaults.into_storage()).fill(0).to_bin();
+
+        let mut builder = ImageBuilder::new(0x100, 3, crc32_new()).unwrap().fill(0);
+        builder
+            .record(1, b"SN0001").unwrap()
+            .record(2, &777u32.to_le_bytes()).unwrap();
+        assert_eq!(image, builder.to_bin());
+    }
+}
@@ -0,0 +1,159 @@
+//! Random sequences of storage operations checked against `HashMap` model
+
+extern crate std;
+
+use std::collections::HashMap;
+use std::vec::Vec;
+
+use proptest::prelude::*;
+use crc::crc32::{Digest, IEEE};
+use crc::CalcType;
+
+use super::*;
+
+const TAGS: usize = 4;
+const MEM_SZ: usize = 0x80;
+
+/// Memory losing power after given number of writes
+struct CutMem {
+    mem: TestMem<MEM_SZ>,
+    writes_left: Option<usize>,
+    sector_words: usize,
+}
+
+impl StorageMemRead for CutMem {
+    type Error = ();
+
+    fn read(&self, offset_words : usize) -> Word {
+        self.mem.read(offset_words)
+    }
+
+    fn read_slice(&self, offset_start : usize, offset_end : usize) -> &'static [Word] {
+        self.mem.read_slice(offset_start, offset_end)
+    }
+
+    fn len(&self) -> usize {
+        self.mem.len()
+    }
+
+    fn sector_words(&self) -> usize {
+        self.sector_words
+    }
+}
+
+impl StorageMem for CutMem {
+    fn write(&mut self, offset_words : usize, word : Word) -> Result<(), Self::Error> {
+        match &mut self.writes_left {
+            Some(0) => Err(()),
+            Some(left) => {
+                *left -= 1;
+                self.mem.write(offset_words, word)
+            }
+            None => self.mem.write(offset_words, word),
+        }
+    }
+
+    fn erase(&mut self, sector: usize) -> Result<(), Self::Error> {
+        if self.writes_left == Some(0) {
+            return Err(());
+        }
+        self.mem.0[sector * self.sector_words .. (sector + 1) * self.sector_words].fill(!0);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    Update { tag: Word, payload: Vec<u8> },
+    Init,
+    /// Update interrupted after `writes` words, followed by reboot
+    PowerCut { tag: Word, payload: Vec<u8>, writes: usize },
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    let tag = 0 .. TAGS as Word;
+    let payload = proptest::collection::vec(any::<u8>(), 0 .. 24);
+    prop_oneof![
+        4 => (tag.clone(), payload.clone()).prop_map(|(tag, payload)| Op::Update { tag, payload }),
+        1 => Just(Op::Init),
+        1 => (tag, payload, 0 .. 10usize)
+            .prop_map(|(tag, payload, writes)| Op::PowerCut { tag, payload, writes }),
+    ]
+}
+
+fn crc32_new() -> Digest {
+    Digest::new_custom(IEEE, !0u32, 0u32, CalcType::Normal)
+}
+
+fn new_table() -> [RecordDesc; TAGS] {
+    let mut table = [RecordDesc { tag: 0, ptr: None }; TAGS];
+    for (tag, desc) in table.iter_mut().enumerate() {
+        desc.tag = tag as Word;
+    }
+    table
+}
+
+/// Words taken by records of `model` with `tag` set to `payload`
+fn live_words(model: &HashMap<Word, Vec<u8>>, tag: Word, payload: &[u8]) -> usize {
+    let record_words = |payload: &[u8]| Storage::<CutMem, Digest>::header_words() + convert_sz_in_words(payload.len());
+    model.iter()
+        .filter(|(t, _)| **t != tag)
+        .map(|(_, payload)| record_words(payload))
+        .sum::<usize>() + record_words(payload)
+}
+
+fn check_model(storage: Storage<CutMem, Digest>, ops: Vec<Op>) -> Result<(), TestCaseError> {
+    let mut storage = storage.mount(new_table()).unwrap();
+    let mut model: HashMap<Word, Vec<u8>> = HashMap::new();
+    let sector_data_words = storage.storage.mem.sector_words - SECTOR_HEADER_WORDS;
+
+    for op in ops {
+        match op {
+            Op::Update { tag, payload } => {
+                match storage.update(tag, &payload) {
+                    Ok(()) => {
+                        model.insert(tag, payload);
+                    }
+                    // Compaction always makes room for live set fitting in a sector
+                    Err(Error::OutOfMemory) if storage.storage.wear_leveling => prop_assert!(
+                        live_words(&model, tag, &payload) > sector_data_words,
+                        "Out of memory with live set fitting in a sector"
+                    ),
+                    Err(Error::OutOfMemory) | Err(Error::NoHeadroom { .. }) => (),
+                    Err(e) => prop_assert!(false, "Unexpected error {:?}", e),
+                }
+            }
+            Op::Init => {
+                storage.remount().unwrap();
+            }
+            Op::PowerCut { tag, payload, writes } => {
+                storage.storage.mem.writes_left = Some(writes);
+                if storage.update(tag, &payload).is_ok() {
+                    model.insert(tag, payload);
+                }
+                storage.storage.mem.writes_left = None;
+                storage.remount().unwrap();
+            }
+        }
+
+        for tag in 0 .. TAGS as Word {
+            let stored = storage.get(tag).unwrap();
+            prop_assert_eq!(stored, model.get(&tag).map(Vec::as_slice));
+        }
+    }
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn storage_matches_model(ops in proptest::collection::vec(op(), 1 .. 48)) {
+        let mem = CutMem { mem: TestMem::new(), writes_left: None, sector_words: MEM_SZ };
+        check_model(Storage::new(mem, crc32_new()), ops)?;
+    }
+
+    #[test]
+    fn wear_leveling_matches_model(ops in proptest::collection::vec(op(), 1 .. 96)) {
+        let mem = CutMem { mem: TestMem::new(), writes_left: None, sector_words: MEM_SZ / 4 };
+        check_model(Storage::new(mem, crc32_new()).wear_leveling(true), ops)?;
+    }
+}
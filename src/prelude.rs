@@ -1,12 +1,28 @@
 pub use crate::{
     Storage,
+    MountedStorage,
     StorageMem,
+    StorageMemRead,
     RecordDesc,
     Word,
     Error,
     WORD_SZ,
+    StorageHasher,
+    CheckValue,
     StorageHasher32,
+    StorageAuthenticator,
+    Authenticated,
+    StorageCipher,
+    VerifyOnRead,
+    CrcPeripheral,
+    HwCrc,
     InitStats,
+    LogEntry,
+    WearStats,
+    Lifetime,
+    History,
+    Iter,
+    IterLive,
 };
 
 pub use nor_storage_macros::generate_storage_ty;
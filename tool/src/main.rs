@@ -0,0 +1,239 @@
+//! Inspect and edit raw flash images of nor-storage
+//!
+//! Usage:
+//!     nor-storage-tool <image> [list]
+//!     nor-storage-tool <image> set <tag> <hex payload> [-o <out image>]
+//!     nor-storage-tool <image> delete <tag> [-o <out image>]
+//!     nor-storage-tool <image> build <size> [<tag>=<hex payload>]... [--fill <byte>] [--base <addr>]
+//!
+//! `build` writes factory default image, as Intel HEX if image name ends
+//! with `.hex` or raw binary otherwise.
+//!
+//! Records are checked with CRC-32/MPEG-2, same as `crc32_ethernet` in examples.
+
+use std::env;
+use std::fs;
+use std::process;
+
+use crc::crc32::{Digest, IEEE};
+use crc::CalcType;
+
+use nor_storage::prelude::*;
+use nor_storage::FileMem;
+use nor_storage::image::ImageBuilder;
+
+// Tags above are reported as unknown
+const MAX_TAGS: usize = 0x100;
+
+fn crc32_mpeg2() -> Digest {
+    Digest::new_custom(IEEE, !0u32, 0u32, CalcType::Normal)
+}
+
+fn record_table() -> Vec<RecordDesc> {
+    (0 .. MAX_TAGS as Word).map(|tag| RecordDesc { tag, ptr: None }).collect()
+}
+
+fn usage() -> ! {
+    eprintln!("Usage:");
+    eprintln!("    nor-storage-tool <image> [list]");
+    eprintln!("    nor-storage-tool <image> set <tag> <hex payload> [-o <out image>]");
+    eprintln!("    nor-storage-tool <image> delete <tag> [-o <out image>]");
+    eprintln!("    nor-storage-tool <image> build <size> [<tag>=<hex payload>]... [--fill <byte>] [--base <addr>]");
+    process::exit(2);
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("error: {}", msg);
+    process::exit(1);
+}
+
+fn parse_num(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn parse_tag(s: &str) -> Word {
+    match parse_num(s) {
+        Some(tag) if (tag as usize) < MAX_TAGS => tag,
+        _ => fail(&format!("invalid tag `{}`", s)),
+    }
+}
+
+fn parse_hex(s: &str) -> Vec<u8> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    if s.len() % 2 != 0 {
+        fail("hex payload should have even number of digits");
+    }
+    (0 .. s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i .. i + 2], 16).unwrap_or_else(|_| fail("invalid hex payload")))
+        .collect()
+}
+
+fn hex_dump(payload: &[u8]) {
+    for (i, line) in payload.chunks(16).enumerate() {
+        let hex: Vec<_> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = line
+            .iter()
+            .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+            .collect();
+        println!("        {:04x}: {:<47}  |{}|", i * 16, hex.join(" "), ascii);
+    }
+}
+
+fn print_row(header: Option<(Word, usize)>, offset: usize, status: &str) {
+    let (tag, size) = match header {
+        Some((tag, size)) => (tag.to_string(), size.to_string()),
+        None => ("?".to_string(), "?".to_string()),
+    };
+    println!("tag {:>5}  offset 0x{:06x}  size {:>5}  {}", tag, offset * WORD_SZ, size, status);
+}
+
+fn list(storage: &MountedStorage<FileMem, Digest, Vec<RecordDesc>>) {
+    let stats = storage.stats();
+    let scan = storage.scan_log(|entry| match entry {
+        LogEntry::Record { tag, offset, payload } => {
+            let live = storage
+                .table()
+                .get(tag as usize)
+                .and_then(|desc| desc.ptr)
+                .map_or(false, |idx| idx == offset);
+            let status = if live { "live" } else if (tag as usize) < MAX_TAGS { "old" } else { "unknown" };
+            print_row(Some((tag, payload.len())), offset, &format!("crc ok      {}", status));
+            hex_dump(payload);
+        }
+        LogEntry::Corrupted { offset, header } => print_row(header, offset, "crc failed"),
+        LogEntry::Torn { offset, header } => print_row(header, offset, "crc failed  torn"),
+    });
+    scan.unwrap_or_else(|e| fail(&format!("can't scan image: {:?}", e)));
+
+    println!();
+    println!("records:          {}", stats.records);
+    println!("unique tags:      {}", stats.unique_tags);
+    println!("superseded:       {}", stats.superseded);
+    println!("unknown tags:     {}", stats.unknown_tags);
+    println!("crc failures:     {}", stats.crc_failures);
+    println!("torn tail:        {}", stats.torn_tail);
+    println!("words wasted:     {}", stats.words_wasted);
+    println!("used bytes:       {} / {}", storage.len(), storage.capacity());
+    if let Some(idx) = stats.first_corruption {
+        println!("first corruption: 0x{:06x}", idx * WORD_SZ);
+    }
+}
+
+fn build(image: &str, size: &str, records: &[String], fill: Option<String>, base: Option<String>) {
+    let size = parse_num(size).unwrap_or_else(|| fail("invalid image size")) as usize;
+    let fill = fill.map_or(0xFF, |f| parse_num(&f).filter(|f| *f <= 0xFF).unwrap_or_else(|| fail("invalid fill value")) as u8);
+    let base = base.map_or(0, |b| parse_num(&b).unwrap_or_else(|| fail("invalid base address")));
+
+    let mut builder = ImageBuilder::new(size, MAX_TAGS, crc32_mpeg2())
+        .unwrap_or_else(|e| fail(&format!("can't create image: {:?}", e)))
+        .fill(fill);
+    for record in records {
+        let (tag, payload) = match record.find('=') {
+            Some(pos) => (&record[.. pos], &record[pos + 1 ..]),
+            None => fail(&format!("record `{}` should be <tag>=<hex payload>", record)),
+        };
+        builder
+            .record(parse_tag(tag), &parse_hex(payload))
+            .unwrap_or_else(|e| fail(&format!("can't add record: {:?}", e)));
+    }
+
+    let res = if image.ends_with(".hex") {
+        fs::write(image, builder.to_intel_hex(base))
+    } else {
+        fs::write(image, builder.to_bin())
+    };
+    res.unwrap_or_else(|e| fail(&format!("can't save `{}`: {}", image, e)));
+}
+
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    match args.iter().position(|a| a == name) {
+        Some(pos) if pos + 1 < args.len() => {
+            let value = args.remove(pos + 1);
+            args.remove(pos);
+            Some(value)
+        }
+        Some(_) => usage(),
+        None => None,
+    }
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+
+    let out = take_option(&mut args, "-o");
+    let fill = take_option(&mut args, "--fill");
+    let base = take_option(&mut args, "--base");
+
+    let image = match args.first() {
+        Some(image) => image.clone(),
+        None => usage(),
+    };
+
+    if args.get(1).map(String::as_str) == Some("build") {
+        match args.get(2) {
+            Some(size) => build(&image, size, &args[3 ..], fill, base),
+            None => usage(),
+        }
+        return;
+    }
+    let mem = FileMem::open(&image).unwrap_or_else(|e| fail(&format!("can't load `{}`: {}", image, e)));
+    let capacity = mem.len();
+
+    let mut storage = Storage::new(mem, crc32_mpeg2())
+        .mount(record_table())
+        .unwrap_or_else(|e| fail(&format!("can't scan image: {:?}", e)));
+
+    let storage = match args.get(1).map(String::as_str) {
+        None | Some("list") if args.len() <= 2 => {
+            list(&storage);
+            return;
+        }
+        Some("set") if args.len() == 4 => {
+            let tag = parse_tag(&args[2]);
+            let payload = parse_hex(&args[3]);
+            storage
+                .update(tag, &payload)
+                .unwrap_or_else(|e| fail(&format!("can't update record: {:?}", e)));
+            storage
+        }
+        Some("delete") if args.len() == 3 => {
+            // Log can't forget a record, so rebuild image without it
+            let tag = parse_tag(&args[2]);
+
+            // Records with tags above table can't be updated, so they are
+            // copied as is in front of the rest
+            let mut mem = FileMem::erased(capacity);
+            let mut end = 0;
+            let unknown = storage.iter_live().filter(|(t, _, _)| *t as usize >= MAX_TAGS);
+            for words in unknown.filter_map(|(_, offset, _)| storage.raw_record(offset)) {
+                for word in words {
+                    mem.write(end, *word).unwrap_or_else(|e| fail(&format!("can't copy record: {:?}", e)));
+                    end += 1;
+                }
+            }
+
+            let mut new_storage = Storage::new(mem, crc32_mpeg2())
+                .mount(record_table())
+                .unwrap_or_else(|e| fail(&format!("can't scan image: {:?}", e)));
+            for (t, _, payload) in storage.iter_live() {
+                if t != tag && (t as usize) < MAX_TAGS {
+                    new_storage
+                        .update(t, payload)
+                        .unwrap_or_else(|e| fail(&format!("can't copy record: {:?}", e)));
+                }
+            }
+            new_storage
+        }
+        _ => usage(),
+    };
+
+    let out = out.unwrap_or(image);
+    storage
+        .mem()
+        .save(&out)
+        .unwrap_or_else(|e| fail(&format!("can't save `{}`: {}", out, e)));
+}